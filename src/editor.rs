@@ -4,12 +4,15 @@ use crate::Terminal;
 
 
 extern crate clipboard;
+extern crate rhai;
 
 use clipboard::ClipboardProvider;
 use clipboard::ClipboardContext;
+use rhai::{Dynamic, Engine, Scope, AST};
 
 use std::env;
 use std::fs;
+use std::path::PathBuf;
 
 use std::time::{Duration, SystemTime, Instant};
 use termion::color;
@@ -19,10 +22,12 @@ use std::io::{Error, Write};
 const STATUS_FG_COLOR: color::Rgb = color::Rgb(63, 63, 63);
 const STATUS_BG_COLOR: color::Rgb = color::Rgb(239, 239, 239); //const STATUS_BG_COLOR: color::Rgb = color::Rgb(39, 40, 34);
 const BG_COLOR: color::Rgb = color::Rgb(239, 239, 239); //const BG_COLOR: color::Rgb = color::Rgb(39, 40, 34);
+const SELECTION_BG_COLOR: color::Rgb = color::Rgb(200, 200, 200);
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const QUIT_TIMES: u8 = 3;
 const BACKUP_AT:u32 = 10;
 const CACHE_FILE:&str="tmp";
+const TAB_STOP: usize = 4;
 
 const SET_BG:bool = false;
 
@@ -33,7 +38,7 @@ pub enum SearchDirection {
     Backward,
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, PartialEq)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -52,18 +57,284 @@ impl StatusMessage {
     }
 }
 
+#[derive(Clone)]
+enum ScriptOp {
+    Insert(Position, char),
+    Delete(Position),
+    MoveCursor(Position),
+}
+
+/// Handle passed into user scripts: cursor position, current row text, and
+/// the edits the script wants to make. Edits are queued as `ScriptOp`s and
+/// applied to the `Document` once the script call returns, since rhai
+/// doesn't let us hand a script a live borrow of the editor.
+#[derive(Clone)]
+struct ScriptApi {
+    cursor: Position,
+    row_text: String,
+    ops: Vec<ScriptOp>,
+}
+impl ScriptApi {
+    fn cursor_x(&mut self) -> i64 {
+        self.cursor.x as i64
+    }
+    fn cursor_y(&mut self) -> i64 {
+        self.cursor.y as i64
+    }
+    fn row_text(&mut self) -> String {
+        self.row_text.clone()
+    }
+    fn insert_char(&mut self, c: char) {
+        self.ops.push(ScriptOp::Insert(self.cursor.clone(), c));
+        self.cursor.x = self.cursor.x.saturating_add(1);
+    }
+    fn delete_char(&mut self) {
+        self.ops.push(ScriptOp::Delete(self.cursor.clone()));
+    }
+    fn move_cursor(&mut self, x: i64, y: i64) {
+        self.cursor = Position {
+            x: x.max(0) as usize,
+            y: y.max(0) as usize,
+        };
+        self.ops.push(ScriptOp::MoveCursor(self.cursor.clone()));
+    }
+}
+
+fn config_dir() -> PathBuf {
+    let base = env::var("XDG_CONFIG_HOME")
+        .or_else(|_| env::var("HOME").map(|home| format!("{}/.config", home)))
+        .unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(base).join("editrs")
+}
+
+fn build_script_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .register_type::<ScriptApi>()
+        .register_fn("cursor_x", ScriptApi::cursor_x)
+        .register_fn("cursor_y", ScriptApi::cursor_y)
+        .register_fn("row_text", ScriptApi::row_text)
+        .register_fn("insert_char", ScriptApi::insert_char)
+        .register_fn("delete_char", ScriptApi::delete_char)
+        .register_fn("move_cursor", ScriptApi::move_cursor);
+    engine
+}
+
+fn load_user_script(engine: &Engine) -> Option<AST> {
+    let path = config_dir().join("init.rhai");
+    let source = fs::read_to_string(path).ok()?;
+    engine.compile(&source).ok()
+}
+
+#[derive(Clone, PartialEq)]
+enum EditKind {
+    Insert,
+    /// `forward` is `true` for a `Key::Delete`-style delete, where the
+    /// cursor sits at the start of the deleted span and never moves, and
+    /// `false` for a Backspace-style delete, where the cursor sat past the
+    /// end of the span before each character was removed. Undo needs this
+    /// to know which end of the span to restore the cursor to.
+    Delete { forward: bool },
+}
+
+/// A single reversible edit: inserting or removing `text` starting at
+/// `position`. Undo replays the inverse; redo replays it again.
+#[derive(Clone)]
+struct EditEntry {
+    kind: EditKind,
+    position: Position,
+    text: String,
+}
+
+/// A single open file along with the view state (cursor, scroll offset,
+/// active search highlight) that used to live directly on `Editor`.
+struct Buffer {
+    document: Document,
+    cursor_position: Position,
+    offset: Position,
+    highlighted_word: Option<String>,
+    selection_anchor: Option<Position>,
+    undo_stack: Vec<EditEntry>,
+    redo_stack: Vec<EditEntry>,
+}
+impl Buffer {
+    /// Inserts `c` at `position` and records the edit for undo.
+    fn insert_char(&mut self, position: &Position, c: char) {
+        self.document.insert(position, c);
+        self.push_edit(EditKind::Insert, position.clone(), c.to_string());
+    }
+    /// Deletes the character at `position` and records the edit for undo.
+    /// `forward` distinguishes a `Key::Delete` (cursor stays put) from a
+    /// Backspace (cursor already stepped left onto `position`).
+    fn delete_char(&mut self, position: &Position, forward: bool) {
+        let text = self.char_at(position);
+        self.document.delete(position);
+        self.push_edit(EditKind::Delete { forward }, position.clone(), text);
+    }
+    /// The character removed by a delete at `position`, or a newline if
+    /// `position` sits at the end of its row (a line-merging delete).
+    fn char_at(&self, position: &Position) -> String {
+        match self.document.row(position.y) {
+            Some(row) if position.x < row.len() => {
+                Editor::render_tokens(&row.render(position.x, position.x + 1))
+                    .into_iter()
+                    .filter_map(|token| match token {
+                        RenderToken::Char(c) => Some(c),
+                        RenderToken::Escape(_) => None,
+                    })
+                    .collect()
+            }
+            _ => "\n".to_string(),
+        }
+    }
+    /// Pushes a new undo entry, merging it into the previous one when both
+    /// are plain word characters that extend each other in place, so a run
+    /// of typing or backspacing undoes as one chunk. A word boundary,
+    /// newline, or cursor jump (a position that doesn't line up) starts a
+    /// fresh entry instead.
+    fn push_edit(&mut self, kind: EditKind, position: Position, text: String) {
+        self.redo_stack.clear();
+        let is_word_text = |s: &str| !s.chars().any(|c| c == '\n' || c.is_whitespace());
+        let coalesced = self.undo_stack.last_mut().map_or(false, |last| {
+            if last.kind != kind
+                || last.position.y != position.y
+                || !is_word_text(&last.text)
+                || !is_word_text(&text)
+            {
+                return false;
+            }
+            match kind {
+                EditKind::Insert
+                    if position.x == last.position.x.saturating_add(last.text.chars().count()) =>
+                {
+                    last.text.push_str(&text);
+                    true
+                }
+                EditKind::Delete { forward: false }
+                    if position.x.saturating_add(1) == last.position.x =>
+                {
+                    last.position = position.clone();
+                    last.text = format!("{}{}", text, last.text);
+                    true
+                }
+                EditKind::Delete { forward: true } if position == last.position => {
+                    last.text.push_str(&text);
+                    true
+                }
+                _ => false,
+            }
+        });
+        if !coalesced {
+            self.undo_stack.push(EditEntry { kind, position, text });
+        }
+    }
+    /// Re-inserts `text` at `position`, one character at a time, and
+    /// returns the position just past the inserted text. `text` may span
+    /// several rows (a multi-line cut or paste), so a `\n` advances to
+    /// `(0, y + 1)` instead of just bumping `x` on the same row.
+    fn replay_insert(&mut self, position: &Position, text: &str) -> Position {
+        let mut position = position.clone();
+        for c in text.chars() {
+            self.document.insert(&position, c);
+            if c == '\n' {
+                position.y = position.y.saturating_add(1);
+                position.x = 0;
+            } else {
+                position.x = position.x.saturating_add(1);
+            }
+        }
+        position
+    }
+    /// Deletes `text.chars().count()` characters starting at `position`
+    /// and returns `position`.
+    fn replay_delete(&mut self, position: &Position, text: &str) -> Position {
+        for _ in text.chars() {
+            self.document.delete(position);
+        }
+        position.clone()
+    }
+    /// Undoes the most recent edit, moving it to the redo stack, and
+    /// returns the cursor position it should leave behind.
+    fn undo(&mut self) -> Option<Position> {
+        let entry = self.undo_stack.pop()?;
+        let cursor = match entry.kind {
+            EditKind::Insert => self.replay_delete(&entry.position, &entry.text),
+            // Forward delete: the cursor never moved, so it belongs back
+            // at the start of the span regardless of what reinserting
+            // the text leaves it at.
+            EditKind::Delete { forward: true } => {
+                self.replay_insert(&entry.position, &entry.text);
+                entry.position.clone()
+            }
+            // Backspace: the cursor sat past the end of the span before
+            // any of it was deleted.
+            EditKind::Delete { forward: false } => {
+                self.replay_insert(&entry.position, &entry.text)
+            }
+        };
+        self.redo_stack.push(entry);
+        Some(cursor)
+    }
+    /// Re-applies the most recently undone edit, moving it back onto the
+    /// undo stack, and returns the cursor position it should leave behind.
+    fn redo(&mut self) -> Option<Position> {
+        let entry = self.redo_stack.pop()?;
+        let cursor = match entry.kind {
+            EditKind::Insert => self.replay_insert(&entry.position, &entry.text),
+            EditKind::Delete { .. } => self.replay_delete(&entry.position, &entry.text),
+        };
+        self.undo_stack.push(entry);
+        Some(cursor)
+    }
+    fn open(file_name: &str) -> Result<Self, std::io::Error> {
+        let document = Document::open(file_name)?;
+        Ok(Self {
+            document,
+            cursor_position: Position::default(),
+            offset: Position::default(),
+            highlighted_word: None,
+            selection_anchor: None,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+        })
+    }
+}
+impl Default for Buffer {
+    fn default() -> Self {
+        Self {
+            document: Document::default(),
+            cursor_position: Position::default(),
+            offset: Position::default(),
+            highlighted_word: None,
+            selection_anchor: None,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+        }
+    }
+}
+
 pub struct Editor {
     should_quit: bool,
     terminal: Terminal,
-    cursor_position: Position,
-    offset: Position,
-    document: Document,
+    buffers: Vec<Buffer>,
+    active_buffer: usize,
     status_message: StatusMessage,
     quit_times: u8,
-    highlighted_word: Option<String>,
     since_last_backup:u32,
     editor_cache:String,
     init_time:SystemTime,
+    script_engine: Engine,
+    user_script: Option<AST>,
+    last_frame: Vec<String>,
+    last_raw_size: (u16, u16),
+}
+
+/// A `Row::render` token: either a single on-screen character, or a whole
+/// escape sequence (syntax/search highlighting embeds ANSI SGR codes, e.g.
+/// `\x1b[...m`, which don't occupy a column themselves).
+enum RenderToken {
+    Char(char),
+    Escape(String),
 }
 
 impl Editor {
@@ -85,65 +356,91 @@ impl Editor {
     }
     pub fn default() -> Self {
         let args: Vec<String> = env::args().collect();
-        let mut initial_status = String::from("ctrl-f:'find' | ctrl-s:'save' | ctrl-q:'quit' | ctrl-b:'temporary backup'");
+        let mut initial_status = String::from("ctrl-f:'find' | ctrl-s:'save' | ctrl-q:'quit' | ctrl-n:'buffers' | ctrl-a:'select' | ctrl-c:'copy' | ctrl-k:'cut' | ctrl-z:'undo' | ctrl-y:'redo' | ctrl-b:'temporary backup'");
 
-        let document = if let Some(file_name) = args.get(1) {
-            let doc = Document::open(file_name);
-            if let Ok(doc) = doc {
-                doc
-            } else {
-                initial_status = format!("__err__: cannot open: {}", file_name);
-                Document::default()
+        let mut buffers: Vec<Buffer> = Vec::new();
+        for file_name in args.iter().skip(1) {
+            match Buffer::open(file_name) {
+                Ok(buffer) => buffers.push(buffer),
+                Err(_) => initial_status = format!("__err__: cannot open: {}", file_name),
             }
-        } else {
-            Document::default()
-        };
+        }
+        if buffers.is_empty() {
+            buffers.push(Buffer::default());
+        }
+
+        let script_engine = build_script_engine();
+        let user_script = load_user_script(&script_engine);
+
+        let terminal = Terminal::default().expect("__could_not_initialize_terminal__");
+        // `Terminal::size()` reserves 2 rows for the status/message bars, so
+        // it never matches the raw size `termion::terminal_size()` reports;
+        // track the raw size separately for `handle_resize` to compare against.
+        let last_raw_size = termion::terminal_size()
+            .unwrap_or((terminal.size().width, terminal.size().height.saturating_add(2)));
 
         Self {
             should_quit: false,
-            terminal: Terminal::default().expect("__could_not_initialize_terminal__"),
-            document,
-            cursor_position: Position::default(),
-            offset: Position::default(),
+            terminal,
+            buffers,
+            active_buffer: 0,
             status_message: StatusMessage::from(initial_status),
             quit_times: QUIT_TIMES,
-            highlighted_word: None,
             since_last_backup: 0,
             editor_cache: CACHE_FILE.to_string(),
-            init_time: SystemTime::now(), 
+            init_time: SystemTime::now(),
+            script_engine,
+            user_script,
+            last_frame: Vec::new(),
+            last_raw_size,
         }
     }
 
+    fn buffer(&self) -> &Buffer {
+        &self.buffers[self.active_buffer]
+    }
+    fn buffer_mut(&mut self) -> &mut Buffer {
+        &mut self.buffers[self.active_buffer]
+    }
+    /// Whether any open buffer has unsaved changes, not just the active one.
+    fn any_buffer_dirty(&self) -> bool {
+        self.buffers.iter().any(|b| b.document.is_dirty())
+    }
+
 
     fn refresh_ui(&mut self) -> Result<(), std::io::Error> {
         Terminal::cursor_hide();
-        Terminal::cursor_position(&Position::default());
-        self.document.highlight(
-            &self.highlighted_word,
-            Some(self.offset.y.saturating_add(self.terminal.size().height as usize),),
-        );
-        self.draw_rows();
-        self.draw_status_bar();
-        self.draw_message_bar();
+        self.handle_resize();
+        let height = self.terminal.size().height as usize;
+        let offset_y = self.buffer().offset.y;
+        let highlighted_word = self.buffer().highlighted_word.clone();
+        self.buffer_mut()
+            .document
+            .highlight(&highlighted_word, Some(offset_y.saturating_add(height)));
+        let frame = self.build_frame();
+        self.render_frame(frame);
+        let render_x = self.render_cursor_x();
+        let cursor_position = self.buffer().cursor_position.clone();
+        let offset = self.buffer().offset.clone();
         Terminal::cursor_position(&Position {
-            x: self.cursor_position.x.saturating_sub(self.offset.x),
-            y: self.cursor_position.y.saturating_sub(self.offset.y),
+            x: render_x.saturating_sub(offset.x),
+            y: cursor_position.y.saturating_sub(offset.y),
         });
-        
+
         Terminal::cursor_show();
         Terminal::flush()
     }
     fn save(&mut self) {
-        if self.document.file_name.is_none() {
+        if self.buffer().document.file_name.is_none() {
             let new_name = self.prompt("__save_as__: ", |_, _, _| {}).unwrap_or(None);
             if new_name.is_none() {
                 self.status("__save_aborted__", true);
                 return;
             }
-            self.document.file_name = new_name;
+            self.buffer_mut().document.file_name = new_name;
         }
 
-        if self.document.save().is_ok() {
+        if self.buffer_mut().document.save().is_ok() {
             self.status("__save_ok__", false);
         } else {
             self.status("__error_saving__", false);
@@ -153,16 +450,122 @@ impl Editor {
         let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
         let data = ctx.get_contents().unwrap();
         self.status("__pasted__", false);
+        let cursor_position = self.buffer().cursor_position.clone();
         for e in data.chars().rev() {
-            self.document.insert(&self.cursor_position, e);
+            self.buffer_mut().document.insert(&cursor_position, e);
+        }
+        if !data.is_empty() {
+            self.buffer_mut().push_edit(EditKind::Insert, cursor_position, data);
+        }
+    }
+    fn begin_selection(&mut self) {
+        let cursor_position = self.buffer().cursor_position.clone();
+        self.buffer_mut().selection_anchor = Some(cursor_position);
+        self.status("__selection_started__", false);
+    }
+    /// Start and end of the current selection, in document order. `None`
+    /// when no selection is active.
+    fn normalized_selection(&self) -> Option<(Position, Position)> {
+        let anchor = self.buffer().selection_anchor.clone()?;
+        let cursor = self.buffer().cursor_position.clone();
+        if (anchor.y, anchor.x) <= (cursor.y, cursor.x) {
+            Some((anchor, cursor))
+        } else {
+            Some((cursor, anchor))
+        }
+    }
+    fn selected_text(&self) -> Option<String> {
+        let (start, end) = self.normalized_selection()?;
+        let mut result = String::new();
+        for y in start.y..=end.y {
+            // `end.y` can sit on the one-past-last-line virtual cursor row
+            // (move_cursor lets it go there), which has no backing row --
+            // treat it as empty instead of throwing away the whole selection.
+            let row = match self.buffer().document.row(y) {
+                Some(row) => row,
+                None => continue,
+            };
+            let chars: Vec<char> = Self::render_tokens(&row.render(0, row.len()))
+                .into_iter()
+                .filter_map(|token| match token {
+                    RenderToken::Char(c) => Some(c),
+                    RenderToken::Escape(_) => None,
+                })
+                .collect();
+            let from = if y == start.y { start.x } else { 0 }.min(chars.len());
+            let to = if y == end.y { end.x } else { chars.len() }.min(chars.len());
+            result.extend(&chars[from..to]);
+            if y != end.y {
+                result.push('\n');
+            }
+        }
+        Some(result)
+    }
+    fn copy_selection(&mut self) {
+        match self.selected_text() {
+            Some(text) if !text.is_empty() => {
+                let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
+                ctx.set_contents(text).unwrap();
+                self.status("__copied__", false);
+            }
+            _ => self.status("__err__:no selection", false),
+        }
+    }
+    fn cut_selection(&mut self) {
+        let start = match self.normalized_selection() {
+            Some((start, _)) => start,
+            None => {
+                self.status("__err__:no selection", false);
+                return;
+            }
+        };
+        let text = match self.selected_text() {
+            Some(text) if !text.is_empty() => text,
+            _ => {
+                self.status("__err__:no selection", false);
+                return;
+            }
+        };
+        let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
+        ctx.set_contents(text.clone()).unwrap();
+        for _ in text.chars() {
+            self.buffer_mut().document.delete(&start);
+        }
+        self.buffer_mut()
+            .push_edit(EditKind::Delete { forward: true }, start.clone(), text);
+        self.buffer_mut().cursor_position = start;
+        self.buffer_mut().selection_anchor = None;
+        self.scroll();
+        self.status("__cut__", false);
+    }
+    fn undo(&mut self) {
+        match self.buffer_mut().undo() {
+            Some(cursor) => {
+                self.buffer_mut().cursor_position = cursor;
+                self.buffer_mut().selection_anchor = None;
+                self.scroll();
+                self.status("__undo__", false);
+            }
+            None => self.status("__err__:nothing to undo", false),
+        }
+    }
+    fn redo(&mut self) {
+        match self.buffer_mut().redo() {
+            Some(cursor) => {
+                self.buffer_mut().cursor_position = cursor;
+                self.buffer_mut().selection_anchor = None;
+                self.scroll();
+                self.status("__redo__", false);
+            }
+            None => self.status("__err__:nothing to redo", false),
         }
     }
     fn backup(&mut self){
-        let doc = &self.document;
+        let doc = &self.buffer().document;
         let cache_file = if let Some(fname) = &doc.file_name {
             format!("{}.tmp", fname)
         } else {
-            self.editor_cache.clone()
+            format!("{}.{}", self.editor_cache, self.active_buffer)
         };
         let mut file = fs::File::create(&cache_file).unwrap();
         let lines = doc.doc_read();
@@ -181,19 +584,243 @@ impl Editor {
     }
 
     fn command_mode(&mut self){
-        let mut command = String::from("::");
+        if let Ok(Some(command)) = self.prompt(":", |_, _, _| {}) {
+            self.execute_command(&command);
+        }
+    }
+
+    fn execute_command(&mut self, command: &str) {
+        let command = command.trim();
+        if command.is_empty() {
+            return;
+        }
+        if let Some(spec) = command.strip_prefix("s/") {
+            self.substitute(spec);
+            return;
+        }
+        let mut parts = command.splitn(2, ' ');
+        let name = parts.next().unwrap_or("");
+        let args = parts.next().unwrap_or("").trim();
+        match name {
+            "w" => {
+                if !args.is_empty() {
+                    self.buffer_mut().document.file_name = Some(args.to_string());
+                }
+                self.save();
+            }
+            "q" => {
+                if self.any_buffer_dirty() {
+                    self.status("__warn__:unsaved changes, use :q! to discard", false);
+                } else {
+                    self.should_quit = true;
+                }
+            }
+            "q!" => self.should_quit = true,
+            "wq" => {
+                self.save();
+                self.should_quit = true;
+            }
+            "e" => {
+                if args.is_empty() {
+                    self.status("__err__:usage: :e <path>", false);
+                } else {
+                    match Buffer::open(args) {
+                        Ok(buffer) => {
+                            self.buffers.push(buffer);
+                            self.active_buffer = self.buffers.len() - 1;
+                        }
+                        Err(_) => self.status(&format!("__err__:cannot open: {}", args), false),
+                    }
+                }
+            }
+            "goto" => {
+                if let Ok(line) = args.parse::<usize>() {
+                    let target = line.saturating_sub(1).min(self.buffer().document.len());
+                    self.buffer_mut().cursor_position.y = target;
+                    self.buffer_mut().cursor_position.x = 0;
+                    self.scroll();
+                } else {
+                    self.status("__err__:usage: :goto <line>", false);
+                }
+            }
+            _ => self.run_user_command(name, args),
+        }
+    }
+
+    fn substitute(&mut self, spec: &str) {
+        let parts: Vec<&str> = spec.split('/').collect();
+        if parts.len() < 2 || parts[0].is_empty() {
+            self.status("__err__:usage: :s/pat/rep/", false);
+            return;
+        }
+        let pattern = parts[0];
+        let replacement = parts[1];
+        let y = self.buffer().cursor_position.y;
+        let row_text: String = match self.buffer().document.row(y) {
+            Some(row) => Self::render_tokens(&row.render(0, row.len()))
+                .into_iter()
+                .filter_map(|token| match token {
+                    RenderToken::Char(c) => Some(c),
+                    RenderToken::Escape(_) => None,
+                })
+                .collect(),
+            None => return,
+        };
+        let index = match row_text.find(pattern) {
+            // `str::find` returns a byte offset; `Position.x` is a char
+            // index everywhere else in this file, so convert before use.
+            Some(byte_index) => row_text[..byte_index].chars().count(),
+            None => {
+                self.status(&format!("__err__:pattern not found: {}", pattern), false);
+                return;
+            }
+        };
+        for _ in pattern.chars() {
+            self.buffer_mut().delete_char(&Position { x: index, y }, true);
+        }
+        let mut position = Position { x: index, y };
+        for c in replacement.chars() {
+            self.buffer_mut().insert_char(&position, c);
+            position.x = position.x.saturating_add(1);
+        }
+        self.status("__substituted__", false);
+    }
+
+    fn run_user_command(&mut self, name: &str, args: &str) {
+        let ast = match &self.user_script {
+            Some(ast) => ast.clone(),
+            None => {
+                self.status(&format!("__err__:unknown command: {}", name), false);
+                return;
+            }
+        };
+        let row_text: String = self
+            .buffer()
+            .document
+            .row(self.buffer().cursor_position.y)
+            .map(|row| {
+                Self::render_tokens(&row.render(0, row.len()))
+                    .into_iter()
+                    .filter_map(|token| match token {
+                        RenderToken::Char(c) => Some(c),
+                        RenderToken::Escape(_) => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut api = ScriptApi {
+            cursor: self.buffer().cursor_position.clone(),
+            row_text,
+            ops: Vec::new(),
+        };
+        let call_args: Vec<Dynamic> = args
+            .split_whitespace()
+            .map(|arg| Dynamic::from(arg.to_string()))
+            .collect();
+        let mut scope = Scope::new();
+        // call_fn_raw's `this_ptr` binds rhai's implicit `this`, but it's
+        // typed `Option<&mut Dynamic>`, not `Option<&mut ScriptApi>` -- wrap
+        // the handle so scripts can still mutate it via `this.insert_char(..)`.
+        let mut api_dynamic = Dynamic::from(api);
+        let result = self.script_engine.call_fn_raw(
+            &mut scope,
+            &ast,
+            false,
+            false,
+            name,
+            Some(&mut api_dynamic),
+            call_args,
+        );
+        match result {
+            Ok(_) => {
+                let api = api_dynamic.cast::<ScriptApi>();
+                self.apply_script_ops(api.ops);
+            }
+            Err(_) => self.status(&format!("__err__:unknown command: {}", name), false),
+        }
+    }
+
+    fn apply_script_ops(&mut self, ops: Vec<ScriptOp>) {
+        for op in ops {
+            match op {
+                ScriptOp::Insert(position, c) => self.buffer_mut().insert_char(&position, c),
+                ScriptOp::Delete(position) => self.buffer_mut().delete_char(&position, true),
+                ScriptOp::MoveCursor(position) => self.buffer_mut().cursor_position = position,
+            }
+        }
+        self.status("__ran_script_command__", false);
+    }
+
+    fn buffer_menu(&mut self) {
+        let mut selected = self.active_buffer;
         loop {
-            if let Ok(k) = Terminal::read_key() {
-                match k {
-                    Key::Char(c) => { 
-                        self.status(&command, true);
-                        command.push(c);
-                
-                    },
-                    _ => break
+            if self.draw_buffer_menu(selected).is_err() {
+                break;
+            }
+            let key = match Terminal::read_key() {
+                Ok(key) => key,
+                Err(_) => break,
+            };
+            match key {
+                Key::Up => selected = selected.saturating_sub(1),
+                Key::Down => selected = selected.saturating_add(1).min(self.buffers.len().saturating_sub(1)),
+                Key::Char('\n') => {
+                    self.active_buffer = selected;
+                    break;
+                }
+                Key::Char('o') => {
+                    if let Ok(Some(path)) = self.prompt("__open__: ", |_, _, _| {}) {
+                        match Buffer::open(&path) {
+                            Ok(buffer) => {
+                                self.buffers.push(buffer);
+                                self.active_buffer = self.buffers.len() - 1;
+                            }
+                            Err(_) => self.status(&format!("__err__:cannot open: {}", path), false),
+                        }
+                    }
+                    break;
+                }
+                Key::Char('c') => {
+                    if self.buffers.len() > 1 {
+                        self.buffers.remove(selected);
+                        // Removing shifts every later buffer down by one
+                        // index, so active_buffer must follow along when it
+                        // sat after the closed buffer.
+                        if selected < self.active_buffer {
+                            self.active_buffer -= 1;
+                        }
+                        selected = selected.min(self.buffers.len() - 1);
+                        self.active_buffer = self.active_buffer.min(self.buffers.len() - 1);
+                    }
                 }
+                Key::Esc => break,
+                _ => (),
             }
         }
+        // The menu painted straight to the terminal instead of going
+        // through build_frame/render_frame, so the diffing renderer has no
+        // idea those rows changed. Force the next refresh_ui to do a full
+        // redraw, same as handle_resize does after invalidating the screen.
+        self.last_frame.clear();
+    }
+
+    fn draw_buffer_menu(&self, selected: usize) -> Result<(), std::io::Error> {
+        Terminal::cursor_hide();
+        Terminal::cursor_position(&Position::default());
+        Terminal::clear_current_line();
+        println!("buffers (up/down:select enter:switch o:open c:close esc:cancel)\r");
+        for (index, buffer) in self.buffers.iter().enumerate() {
+            Terminal::clear_current_line();
+            let file_name = buffer
+                .document
+                .file_name
+                .clone()
+                .unwrap_or_else(|| "[No Name]".to_string());
+            let marker = if index == selected { ">" } else { " " };
+            println!("{} {}\r", marker, file_name);
+        }
+        Terminal::cursor_show();
+        Terminal::flush()
     }
     
     fn status(&mut self, msg:&str, refresh:bool){
@@ -207,7 +834,7 @@ impl Editor {
 
 
     fn search(&mut self) {
-        let old_position = self.cursor_position.clone();
+        let old_position = self.buffer().cursor_position.clone();
         let mut direction = SearchDirection::Forward;
         let query = self
             .prompt(
@@ -223,26 +850,28 @@ impl Editor {
                         Key::Left | Key::Up => direction = SearchDirection::Backward,
                         _ => direction = SearchDirection::Forward,
                     }
+                    let cursor_position = editor.buffer().cursor_position.clone();
                     if let Some(position) =
                         editor
+                            .buffer()
                             .document
-                            .find(&query, &editor.cursor_position, direction)
+                            .find(&query, &cursor_position, direction)
                     {
-                        editor.cursor_position = position;
+                        editor.buffer_mut().cursor_position = position;
                         editor.scroll();
                     } else if moved {
                         editor.move_cursor(Key::Left);
                     }
-                    editor.highlighted_word = Some(query.to_string());
+                    editor.buffer_mut().highlighted_word = Some(query.to_string());
                 },
             )
             .unwrap_or(None);
 
         if query.is_none() {
-            self.cursor_position = old_position;
+            self.buffer_mut().cursor_position = old_position;
             self.scroll();
         }
-        self.highlighted_word = None;
+        self.buffer_mut().highlighted_word = None;
     }
     fn process_keypress(&mut self) -> Result<bool, std::io::Error> {
         let pressed_key = Terminal::read_key()?;
@@ -252,7 +881,7 @@ impl Editor {
         }
         match pressed_key {
             Key::Ctrl('q') => {
-                if self.quit_times > 0 && self.document.is_dirty() {
+                if self.quit_times > 0 && self.any_buffer_dirty() {
                     self.status(&format!("__warn__:unsaved changes. hit ctrl-q {} more times to quit.", self.quit_times), true);
                     self.quit_times -= 1;
                     return Ok(self.quit_times > 0);
@@ -264,15 +893,27 @@ impl Editor {
             Key::Ctrl('b') => self.backup(),
             Key::Ctrl('v') => self.paste(),
             Key::Ctrl('x') => self.command_mode(),
+            Key::Ctrl('n') => self.buffer_menu(),
+            Key::Ctrl('a') => self.begin_selection(),
+            Key::Ctrl('c') => self.copy_selection(),
+            Key::Ctrl('k') => self.cut_selection(),
+            Key::Ctrl('z') => self.undo(),
+            Key::Ctrl('y') => self.redo(),
             Key::Char(c) => {
-                self.document.insert(&self.cursor_position, c);
+                let cursor_position = self.buffer().cursor_position.clone();
+                self.buffer_mut().insert_char(&cursor_position, c);
                 self.move_cursor(Key::Right);
             }
-            Key::Delete => self.document.delete(&self.cursor_position),
+            Key::Delete => {
+                let cursor_position = self.buffer().cursor_position.clone();
+                self.buffer_mut().delete_char(&cursor_position, true);
+            }
             Key::Backspace => {
-                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
+                let cursor_position = self.buffer().cursor_position.clone();
+                if cursor_position.x > 0 || cursor_position.y > 0 {
                     self.move_cursor(Key::Left);
-                    self.document.delete(&self.cursor_position);
+                    let cursor_position = self.buffer().cursor_position.clone();
+                    self.buffer_mut().delete_char(&cursor_position, false);
                 }
             }
             Key::Up
@@ -292,27 +933,72 @@ impl Editor {
         }
         Ok(true)
     }
+    /// Splits `text` into `RenderToken`s so callers can count on-screen
+    /// columns without mistaking embedded highlight escape codes for
+    /// visible characters.
+    fn render_tokens(text: &str) -> Vec<RenderToken> {
+        let mut tokens = Vec::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\u{1b}' {
+                let mut escape = String::from(c);
+                for esc_c in chars.by_ref() {
+                    escape.push(esc_c);
+                    if esc_c == 'm' {
+                        break;
+                    }
+                }
+                tokens.push(RenderToken::Escape(escape));
+            } else {
+                tokens.push(RenderToken::Char(c));
+            }
+        }
+        tokens
+    }
+    /// Translates a logical character index on a row into the on-screen
+    /// column it renders at, expanding tabs to the next `TAB_STOP` boundary.
+    fn render_x_for_row(row: &Row, x: usize) -> usize {
+        let mut render_x = 0;
+        for token in Self::render_tokens(&row.render(0, x)) {
+            if let RenderToken::Char(c) = token {
+                if c == '\t' {
+                    render_x += TAB_STOP - (render_x % TAB_STOP);
+                } else {
+                    render_x += 1;
+                }
+            }
+        }
+        render_x
+    }
+    fn render_cursor_x(&self) -> usize {
+        let Position { x, y } = self.buffer().cursor_position;
+        match self.buffer().document.row(y) {
+            Some(row) => Self::render_x_for_row(row, x),
+            None => x,
+        }
+    }
     fn scroll(&mut self) {
-        let Position { x, y } = self.cursor_position;
+        let y = self.buffer().cursor_position.y;
+        let render_x = self.render_cursor_x();
         let width = self.terminal.size().width as usize;
         let height = self.terminal.size().height as usize;
-        let mut offset = &mut self.offset;
+        let offset = &mut self.buffer_mut().offset;
         if y < offset.y {
             offset.y = y;
         } else if y >= offset.y.saturating_add(height) {
             offset.y = y.saturating_sub(height).saturating_add(1);
         }
-        if x < offset.x {
-            offset.x = x;
-        } else if x >= offset.x.saturating_add(width) {
-            offset.x = x.saturating_sub(width).saturating_add(1);
+        if render_x < offset.x {
+            offset.x = render_x;
+        } else if render_x >= offset.x.saturating_add(width) {
+            offset.x = render_x.saturating_sub(width).saturating_add(1);
         }
     }
     fn move_cursor(&mut self, key: Key) {
         let terminal_height = self.terminal.size().height as usize;
-        let Position { mut y, mut x } = self.cursor_position;
-        let height = self.document.len();
-        let mut width = if let Some(row) = self.document.row(y) {
+        let Position { mut y, mut x } = self.buffer().cursor_position;
+        let height = self.buffer().document.len();
+        let mut width = if let Some(row) = self.buffer().document.row(y) {
             row.len()
         } else {
             0
@@ -329,7 +1015,7 @@ impl Editor {
                     x -= 1;
                 } else if y > 0 {
                     y -= 1;
-                    if let Some(row) = self.document.row(y) {
+                    if let Some(row) = self.buffer().document.row(y) {
                         x = row.len();
                     } else {
                         x = 0;
@@ -362,7 +1048,7 @@ impl Editor {
             Key::End => x = width,
             _ => (),
         }
-        width = if let Some(row) = self.document.row(y) {
+        width = if let Some(row) = self.buffer().document.row(y) {
             row.len()
         } else {
             0
@@ -371,9 +1057,9 @@ impl Editor {
             x = width;
         }
 
-        self.cursor_position = Position { x, y }
+        self.buffer_mut().cursor_position = Position { x, y }
     }
-    fn draw_welcome_message(&self) {
+    fn welcome_message_text(&self) -> String {
         let mut welcome_message = format!("editrs v{}", VERSION);
         let width = self.terminal.size().width as usize;
         let len = welcome_message.len();
@@ -382,86 +1068,230 @@ impl Editor {
         let spaces = " ".repeat(padding.saturating_sub(1));
         welcome_message = format!("~{}{}", spaces, welcome_message);
         welcome_message.truncate(width);
-        println!("{}\r", welcome_message);
+        welcome_message
     }
-    pub fn draw_row(&self, row: &Row) {
+    /// Expands literal tabs in `text` to spaces out to the next `TAB_STOP`
+    /// boundary, using the same column accounting as `render_x_for_row`.
+    /// `Row::render` isn't part of this tree to patch directly, so rows are
+    /// expanded here before they're printed, keeping on-screen glyphs
+    /// aligned with the render-column cursor math instead of however many
+    /// columns the terminal's own tab stops would give them.
+    fn expand_tabs(text: &str) -> String {
+        let mut expanded = String::with_capacity(text.len());
+        let mut column = 0;
+        for token in Self::render_tokens(text) {
+            match token {
+                RenderToken::Escape(escape) => expanded.push_str(&escape),
+                RenderToken::Char('\t') => {
+                    let spaces = TAB_STOP - (column % TAB_STOP);
+                    expanded.extend(std::iter::repeat(' ').take(spaces));
+                    column += spaces;
+                }
+                RenderToken::Char(c) => {
+                    expanded.push(c);
+                    column += 1;
+                }
+            }
+        }
+        expanded
+    }
+    fn row_text(&self, row: &Row, y: usize) -> String {
         let width = self.terminal.size().width as usize;
-        let start = self.offset.x;
-        let end = self.offset.x.saturating_add(width);
-        let row = row.render(start, end);
-        println!("{}\r", row)
+        let start = self.buffer().offset.x;
+        let end = self.buffer().offset.x.saturating_add(width);
+        let expanded = Self::expand_tabs(&row.render(0, row.len()));
+        // Walk tokens rather than `expanded.chars()` directly: escape
+        // sequences from `document.highlight()` survive `expand_tabs`
+        // verbatim and must never be counted as columns when deciding
+        // which slice of the row falls inside [start, end), or they'd
+        // either get truncated mid-escape or shift real text out of view.
+        let mut visible = String::new();
+        let mut column = 0;
+        for token in Self::render_tokens(&expanded) {
+            match token {
+                RenderToken::Escape(escape) => visible.push_str(&escape),
+                RenderToken::Char(c) => {
+                    if column >= start && column < end {
+                        visible.push(c);
+                    }
+                    column += 1;
+                }
+            }
+        }
+        match self.selection_columns_for_row(row, y) {
+            Some((from, to)) => Self::highlight_columns(
+                &visible,
+                from.saturating_sub(start),
+                to.saturating_sub(start),
+            ),
+            None => visible,
+        }
+    }
+    /// Display-column range (post tab-expansion) of the current selection on
+    /// row `y`, or `None` if there's no selection or it doesn't reach `y`.
+    fn selection_columns_for_row(&self, row: &Row, y: usize) -> Option<(usize, usize)> {
+        let (start, end) = self.normalized_selection()?;
+        if y < start.y || y > end.y {
+            return None;
+        }
+        let from_x = if y == start.y { start.x } else { 0 };
+        let to_x = if y == end.y { end.x } else { row.len() };
+        let from = Self::render_x_for_row(row, from_x.min(row.len()));
+        let to = Self::render_x_for_row(row, to_x.min(row.len()));
+        Some((from, to))
+    }
+    /// Wraps the `[from, to)` character range of `line` (already clipped to
+    /// the visible window) in the selection background color.
+    fn highlight_columns(line: &str, from: usize, to: usize) -> String {
+        // `line` may carry embedded highlight escape sequences, so splicing
+        // the selection background in by raw char offset would land inside
+        // an escape or at the wrong visible column -- walk render_tokens
+        // and only count `Char` entries, the same way row_text's windowing
+        // loop already does.
+        let tokens = Self::render_tokens(line);
+        let total_chars = tokens.iter().filter(|t| matches!(t, RenderToken::Char(_))).count();
+        let from = from.min(total_chars);
+        let to = to.min(total_chars);
+        if from >= to {
+            return line.to_string();
+        }
+        let mut result = String::with_capacity(line.len() + 16);
+        let mut column = 0;
+        let mut in_selection = false;
+        for token in tokens {
+            match token {
+                RenderToken::Escape(escape) => result.push_str(&escape),
+                RenderToken::Char(c) => {
+                    if column == from {
+                        result.push_str(&format!("{}", color::Bg(SELECTION_BG_COLOR)));
+                        in_selection = true;
+                    }
+                    if column == to {
+                        result.push_str(&format!("{}", color::Bg(color::Reset)));
+                        in_selection = false;
+                    }
+                    result.push(c);
+                    column += 1;
+                }
+            }
+        }
+        if in_selection {
+            result.push_str(&format!("{}", color::Bg(color::Reset)));
+        }
+        result
     }
     #[allow(clippy::integer_division, clippy::integer_arithmetic)]
-    fn draw_rows(&self) {
+    fn document_lines(&self) -> Vec<String> {
         let height = self.terminal.size().height;
+        let mut lines = Vec::with_capacity(height as usize);
         for terminal_row in 0..height {
-            //Terminal::set_bg_color(BG_COLOR);
-            Terminal::clear_current_line();
-            if let Some(row) = self
-                .document
-                .row(self.offset.y.saturating_add(terminal_row as usize))
-            {
-                self.draw_row(row);
-            } else if self.document.is_empty() && terminal_row == height / 3 {
-                self.draw_welcome_message();
+            let y = self.buffer().offset.y.saturating_add(terminal_row as usize);
+            let line = if let Some(row) = self.buffer().document.row(y) {
+                self.row_text(row, y)
+            } else if self.buffer().document.is_empty() && terminal_row == height / 3 {
+                self.welcome_message_text()
             } else {
-                println!("~\r");
-            }
+                "~".to_string()
+            };
+            lines.push(line);
         }
-        
+        lines
     }
-    fn draw_status_bar(&self) {
+    fn status_bar_text(&self) -> String {
         let mut status;
         let width = self.terminal.size().width as usize;
-        let modified_indicator = if self.document.is_dirty() {
+        let modified_indicator = if self.buffer().document.is_dirty() {
             " (modified)"
         } else {
             ""
         };
 
         let mut file_name = "[No Name]".to_string();
-        if let Some(name) = &self.document.file_name {
+        if let Some(name) = &self.buffer().document.file_name {
             file_name = name.clone();
             file_name.truncate(20);
         }
         status = format!(
-            "{} - {} lines{}",
+            "{} - {} lines{} - buffer {}/{}",
             file_name,
-            self.document.len(),
-            modified_indicator
+            self.buffer().document.len(),
+            modified_indicator,
+            self.active_buffer.saturating_add(1),
+            self.buffers.len()
         );
 
         let line_indicator = format!(
             "{} | {}/{}",
-            self.document.file_type(),
-            self.cursor_position.y.saturating_add(1),
-            self.document.len()
+            self.buffer().document.file_type(),
+            self.buffer().cursor_position.y.saturating_add(1),
+            self.buffer().document.len()
         );
         #[allow(clippy::integer_arithmetic)]
         let len = status.len() + line_indicator.len();
         status.push_str(&" ".repeat(width.saturating_sub(len)));
         status = format!("{}{}", status, line_indicator);
         status.truncate(width);
-        //Terminal::set_bg_color(BG_COLOR);
-        Terminal::set_fg_color(STATUS_FG_COLOR);
-        println!("{}\r", status);
-        Terminal::reset_fg_color();
-        Terminal::reset_bg_color();;
-    }
-    fn draw_message_bar(&self) {
-        //Terminal::set_bg_color(BG_COLOR);
-        Terminal::clear_current_line();
+        status
+    }
+    fn message_bar_text(&self) -> String {
         let message = &self.status_message;
         if Instant::now() - message.time < Duration::new(5, 0) {
             let mut text = message.text.clone();
             text.truncate(self.terminal.size().width as usize);
-            print!("{}", text);
+            text
+        } else {
+            String::new()
+        }
+    }
+    /// Builds the full intended screen (document rows, then the status bar,
+    /// then the message bar) as one string per terminal line.
+    fn build_frame(&self) -> Vec<String> {
+        let mut frame = self.document_lines();
+        frame.push(self.status_bar_text());
+        frame.push(self.message_bar_text());
+        frame
+    }
+    /// Diffs `frame` against the previously rendered frame and only
+    /// repaints the lines that actually changed, instead of clearing and
+    /// reprinting the whole screen every cycle.
+    fn render_frame(&mut self, frame: Vec<String>) {
+        let status_bar_row = frame.len().saturating_sub(2);
+        let message_bar_row = frame.len().saturating_sub(1);
+        let full_redraw = self.last_frame.len() != frame.len();
+        for (index, line) in frame.iter().enumerate() {
+            if !full_redraw && self.last_frame.get(index) == Some(line) {
+                continue;
+            }
+            Terminal::cursor_position(&Position { x: 0, y: index });
+            Terminal::clear_current_line();
+            if index == status_bar_row {
+                Terminal::set_fg_color(STATUS_FG_COLOR);
+                print!("{}\r\n", line);
+                Terminal::reset_fg_color();
+                Terminal::reset_bg_color();
+            } else if index == message_bar_row {
+                // No trailing newline: the message bar is the terminal's
+                // last row, and a newline there scrolls the whole screen.
+                print!("{}", line);
+            } else {
+                print!("{}\r\n", line);
+            }
+        }
+        self.last_frame = frame;
+    }
+    /// Polls the live terminal size and, if it no longer matches the
+    /// cached `Terminal`, re-reads it, invalidates the cached frame so the
+    /// next render is a full redraw, and re-derives scroll against the new
+    /// dimensions.
+    fn handle_resize(&mut self) {
+        if let Ok((width, height)) = termion::terminal_size() {
+            if self.last_raw_size != (width, height) {
+                self.terminal = Terminal::default().expect("__could_not_initialize_terminal__");
+                self.last_raw_size = (width, height);
+                self.last_frame.clear();
+                self.scroll();
+            }
         }
-        //for e in 1..5 {
-        //   Terminal::set_bg_color(BG_COLOR);
-        //    Terminal::clear_current_line();
-        //    println!("...");
-        //}
     }
     fn prompt<C>(&mut self, prompt: &str, mut callback: C) -> Result<Option<String>, std::io::Error>
     where